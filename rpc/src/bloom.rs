@@ -0,0 +1,146 @@
+/*
+ * Copyright 2018 Intel Corporation
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * ------------------------------------------------------------------------------
+ */
+
+//! Computation of the Ethereum `logsBloom` filter, as defined by the
+//! yellow paper (section 4.3.1, the `M` function): a 2048-bit filter built
+//! by hashing each log's address and topics and setting three bits per hash.
+
+use tiny_keccak::Keccak;
+
+/// Number of bytes in an Ethereum bloom filter (2048 bits).
+pub const BLOOM_BYTE_LENGTH: usize = 256;
+
+/// An Ethereum bloom filter, stored big-endian as Ethereum clients expect:
+/// bit `n` lives in byte `255 - n / 8`, bit `n % 8` of that byte.
+pub type Bloom = [u8; BLOOM_BYTE_LENGTH];
+
+/// The address and topics of a single EVM log entry, borrowed from a Seth
+/// transaction receipt.
+pub struct LogEntry<'a> {
+    pub address: &'a [u8],
+    pub topics: &'a [Vec<u8>],
+}
+
+/// Computes the bloom filter for a single receipt's logs.
+pub fn compute_bloom(logs: &[LogEntry]) -> Bloom {
+    let mut bloom = [0u8; BLOOM_BYTE_LENGTH];
+    for log in logs {
+        add_to_bloom(&mut bloom, log.address);
+        for topic in log.topics {
+            add_to_bloom(&mut bloom, topic);
+        }
+    }
+    bloom
+}
+
+/// ORs several per-receipt blooms together into a single block-level bloom.
+pub fn merge_blooms<'a, I>(blooms: I) -> Bloom
+where
+    I: IntoIterator<Item = &'a Bloom>,
+{
+    let mut merged = [0u8; BLOOM_BYTE_LENGTH];
+    for bloom in blooms {
+        for (m, b) in merged.iter_mut().zip(bloom.iter()) {
+            *m |= *b;
+        }
+    }
+    merged
+}
+
+/// Hashes `data` with keccak256 and sets the three bits it selects in `bloom`.
+///
+/// Bytes (0,1), (2,3), and (4,5) of the hash are each interpreted as a
+/// big-endian u16 and masked with `& 0x7FF` to give an 11-bit bit index.
+fn add_to_bloom(bloom: &mut Bloom, data: &[u8]) {
+    let mut hash = [0u8; 32];
+    let mut keccak = Keccak::new_keccak256();
+    keccak.update(data);
+    keccak.finalize(&mut hash);
+
+    for &(hi, lo) in &[(0, 1), (2, 3), (4, 5)] {
+        let index = ((u16::from(hash[hi]) << 8) | u16::from(hash[lo])) & 0x07FF;
+        let byte = BLOOM_BYTE_LENGTH - 1 - (index as usize / 8);
+        let bit = index % 8;
+        bloom[byte] |= 1 << bit;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_logs_yield_zero_bloom() {
+        let bloom = compute_bloom(&[]);
+        assert_eq!(bloom.iter().all(|&b| b == 0), true);
+    }
+
+    #[test]
+    fn single_log_sets_exactly_six_bits() {
+        // address = 0x000102...13, topic = 32 zero bytes with a trailing 1
+        let address: Vec<u8> = (0u8..20).collect();
+        let mut topic = vec![0u8; 32];
+        topic[31] = 1;
+
+        let logs = [LogEntry {
+            address: &address,
+            topics: &[topic],
+        }];
+        let bloom = compute_bloom(&logs);
+
+        let set_bits: u32 = bloom.iter().map(|b| b.count_ones()).sum();
+        assert_eq!(set_bits, 6);
+    }
+
+    #[test]
+    fn known_bit_positions_for_address_and_topic() {
+        // keccak256-derived bit indices computed independently from the
+        // algorithm above: address selects {2014, 501, 1711}, topic
+        // selects {270, 1362, 1554}.
+        let address: Vec<u8> = (0u8..20).collect();
+        let mut topic = vec![0u8; 32];
+        topic[31] = 1;
+
+        let logs = [LogEntry {
+            address: &address,
+            topics: &[topic],
+        }];
+        let bloom = compute_bloom(&logs);
+
+        for index in &[2014u16, 501, 1711, 270, 1362, 1554] {
+            let byte = BLOOM_BYTE_LENGTH - 1 - (*index as usize / 8);
+            let bit = index % 8;
+            assert_eq!(
+                bloom[byte] & (1 << bit),
+                1 << bit,
+                "expected bit {} to be set",
+                index
+            );
+        }
+    }
+
+    #[test]
+    fn merge_blooms_ors_bits_together() {
+        let mut a = [0u8; BLOOM_BYTE_LENGTH];
+        let mut b = [0u8; BLOOM_BYTE_LENGTH];
+        a[0] = 0b0000_0001;
+        b[0] = 0b0000_0010;
+
+        let merged = merge_blooms(&[a, b]);
+        assert_eq!(merged[0], 0b0000_0011);
+    }
+}
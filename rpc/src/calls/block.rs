@@ -15,17 +15,23 @@
  * ------------------------------------------------------------------------------
  */
 
+use bloom;
+use bloom::LogEntry;
 use client::{BlockKey, Error as ClientError, ValidatorClient};
+use flow_control;
 use jsonrpc_core::{Error, Params, Value};
 use protobuf;
+use protobuf::Message;
 use requests::RequestHandler;
-use sawtooth_sdk::messages::block::BlockHeader;
+use sawtooth_sdk::messages::block::{Block, BlockHeader};
+use sawtooth_sdk::messages::transaction::Transaction;
 use sawtooth_sdk::messaging::stream::*;
 use serde_json::Map;
+use std::collections::HashMap;
 use std::str::FromStr;
-use transactions::TransactionKey;
 use transform;
 use transform::make_txn_obj_no_block;
+use trie;
 
 pub fn get_method_list<T>() -> Vec<(String, RequestHandler<T>)>
 where
@@ -43,6 +49,22 @@ where
             "eth_getBlockTransactionCountByNumber".into(),
             get_block_transaction_count_by_number,
         ),
+        (
+            "eth_getUncleCountByBlockHash".into(),
+            get_uncle_count_by_block_hash,
+        ),
+        (
+            "eth_getUncleCountByBlockNumber".into(),
+            get_uncle_count_by_block_number,
+        ),
+        (
+            "eth_getUncleByBlockHashAndIndex".into(),
+            get_uncle_by_block_hash_and_index,
+        ),
+        (
+            "eth_getUncleByBlockNumberAndIndex".into(),
+            get_uncle_by_block_number_and_index,
+        ),
     ]
 }
 
@@ -187,6 +209,98 @@ where
     get_block_transaction_count(block_key, client)
 }
 
+/// Endpoint that returns the number of uncles in a block matching the given block hash
+///
+/// https://github.com/ethereum/wiki/wiki/JSON-RPC#eth_getunclecountbyblockhash
+/// Sawtooth has no notion of uncle blocks, so this returns `0x0` for any
+/// block that exists and `null` otherwise.
+pub fn get_uncle_count_by_block_hash<T>(
+    params: Params,
+    client: ValidatorClient<T>,
+) -> Result<Value, Error>
+where
+    T: MessageSender,
+{
+    info!("eth_getUncleCountByBlockHash");
+
+    let (block_hash,): (String,) = match params.parse() {
+        Ok(t) => t,
+        Err(_) => {
+            return Err(Error::invalid_params("Takes [blockHash: DATA(64)]"));
+        }
+    };
+
+    let block_hash = match block_hash.get(2..) {
+        Some(bh) => String::from(bh),
+        None => {
+            return Err(Error::invalid_params("Invalid block hash, must have 0x"));
+        }
+    };
+
+    get_uncle_count(BlockKey::Signature(block_hash), client)
+}
+
+/// Endpoint that returns the number of uncles in a block matching the given block number
+///
+/// https://github.com/ethereum/wiki/wiki/JSON-RPC#eth_getunclecountbyblocknumber
+pub fn get_uncle_count_by_block_number<T>(
+    params: Params,
+    client: ValidatorClient<T>,
+) -> Result<Value, Error>
+where
+    T: MessageSender,
+{
+    info!("eth_getUncleCountByBlockNumber");
+
+    let (block_num,): (String,) = match params.parse() {
+        Ok(t) => t,
+        Err(_) => {
+            return Err(Error::invalid_params("Takes [blockNum: QUANTITY|TAG]"));
+        }
+    };
+
+    let block_key = match BlockKey::from_str(block_num.as_str()) {
+        Ok(k) => k,
+        Err(_) => {
+            return Err(Error::invalid_params("Invalid block number"));
+        }
+    };
+
+    get_uncle_count(block_key, client)
+}
+
+/// Endpoint that returns an uncle by block hash and uncle index.
+///
+/// https://github.com/ethereum/wiki/wiki/JSON-RPC#eth_getunclebyblockhashandindex
+/// Sawtooth has no notion of uncle blocks, so this always returns `null`.
+pub fn get_uncle_by_block_hash_and_index<T>(
+    _params: Params,
+    _client: ValidatorClient<T>,
+) -> Result<Value, Error>
+where
+    T: MessageSender,
+{
+    info!("eth_getUncleByBlockHashAndIndex");
+
+    Ok(Value::Null)
+}
+
+/// Endpoint that returns an uncle by block number and uncle index.
+///
+/// https://github.com/ethereum/wiki/wiki/JSON-RPC#eth_getunclebyblocknumberandindex
+/// Sawtooth has no notion of uncle blocks, so this always returns `null`.
+pub fn get_uncle_by_block_number_and_index<T>(
+    _params: Params,
+    _client: ValidatorClient<T>,
+) -> Result<Value, Error>
+where
+    T: MessageSender,
+{
+    info!("eth_getUncleByBlockNumberAndIndex");
+
+    Ok(Value::Null)
+}
+
 /// Returns information about a block as a `json_rpc::Value` object
 fn get_block_obj<T>(
     block_key: BlockKey,
@@ -217,6 +331,21 @@ where
         }
     };
 
+    // Every receipt gets bloom-hashed and RLP-encoded into the
+    // transactions/receipts tries below regardless of `full`, so the
+    // charge has to scale with the block's transaction count either way;
+    // `full=true` additionally builds a full transaction object per
+    // transaction, but that's not what dominates the cost here.
+    let txn_count = block
+        .batches
+        .iter()
+        .fold(0, |acc, batch| acc + batch.transactions.len());
+    flow_control::FLOW_CONTROL.charge(
+        flow_control::DEFAULT_CALLER,
+        "get_block_obj",
+        txn_count,
+    )?;
+
     let mut bob = Map::new();
     bob.insert(
         String::from("number"),
@@ -243,33 +372,98 @@ where
         }
     };
 
+    // Transactions are already present in full in the block's batches, so
+    // both the `full` object and the transactions trie can be built
+    // without re-fetching each transaction from the validator one at a
+    // time.
+    let txns_by_sig = index_transactions_by_signature(&block);
+
     let mut transactions = Vec::new();
     let mut gas: u64 = 0;
+    let mut receipt_blooms = Vec::new();
+    let mut transaction_rlps = Vec::new();
+    let mut receipt_rlps = Vec::new();
     for (txn_id, receipt) in receipts {
+        let txn = match txns_by_sig.get(&txn_id) {
+            Some(&txn) => txn,
+            None => {
+                error!(
+                    "Receipt for {} has no matching transaction in block",
+                    txn_id
+                );
+                return Err(Error::internal_error());
+            }
+        };
+
         if full {
-            let (txn, _) =
-                match client.get_transaction_and_block(&TransactionKey::Signature(txn_id)) {
-                    Ok(t) => t,
-                    Err(error) => {
-                        error!("Error getting transactions: {:?}", error);
-                        return Err(Error::internal_error());
-                    }
-                };
-            transactions.push(make_txn_obj_no_block(&txn))
+            transactions.push(make_txn_obj_no_block(txn))
         } else {
             transactions.push(transform::hex_prefix(&txn_id));
         }
         gas += receipt.gas_used;
+
+        let logs: Vec<LogEntry> = receipt
+            .log_entries
+            .iter()
+            .map(|log| LogEntry {
+                address: &log.address,
+                topics: &log.topics,
+            })
+            .collect();
+        let receipt_bloom = bloom::compute_bloom(&logs);
+
+        let log_rlps: Vec<Vec<u8>> = receipt
+            .log_entries
+            .iter()
+            .map(|log| {
+                let topic_rlps: Vec<Vec<u8>> =
+                    log.topics.iter().map(|t| trie::rlp_bytes(t)).collect();
+                trie::rlp_list(&[
+                    trie::rlp_bytes(&log.address),
+                    trie::rlp_list(&topic_rlps),
+                    trie::rlp_bytes(&log.data),
+                ])
+            })
+            .collect();
+        let status: Vec<u8> = if receipt.result { vec![1] } else { Vec::new() };
+        receipt_rlps.push(trie::rlp_list(&[
+            trie::rlp_bytes(&status),
+            trie::rlp_bytes(&trie::be_bytes(gas)),
+            trie::rlp_bytes(&receipt_bloom),
+            trie::rlp_list(&log_rlps),
+        ]));
+
+        let txn_bytes = match txn.write_to_bytes() {
+            Ok(b) => b,
+            Err(error) => {
+                error!("Error serializing transaction: {:?}", error);
+                return Err(Error::internal_error());
+            }
+        };
+        transaction_rlps.push(trie::rlp_bytes(&txn_bytes));
+
+        receipt_blooms.push(receipt_bloom);
     }
     bob.insert(String::from("transactions"), Value::Array(transactions));
     bob.insert(String::from("gasUsed"), transform::num_to_hex(&gas));
 
+    let block_bloom = bloom::merge_blooms(&receipt_blooms);
+
     // No corollaries in Sawtooth
     bob.insert(String::from("nonce"), transform::zerobytes(8));
     bob.insert(String::from("sha3Uncles"), transform::zerobytes(32));
-    bob.insert(String::from("logsBloom"), transform::zerobytes(256));
-    bob.insert(String::from("transactionsRoot"), transform::zerobytes(32));
-    bob.insert(String::from("receiptsRoot"), transform::zerobytes(32));
+    bob.insert(
+        String::from("logsBloom"),
+        transform::hex_prefix(&block_bloom.to_vec()),
+    );
+    bob.insert(
+        String::from("transactionsRoot"),
+        transform::hex_prefix(&trie::ordered_trie_root(&transaction_rlps).to_vec()),
+    );
+    bob.insert(
+        String::from("receiptsRoot"),
+        transform::hex_prefix(&trie::ordered_trie_root(&receipt_rlps).to_vec()),
+    );
     bob.insert(String::from("miner"), transform::zerobytes(20));
     bob.insert(String::from("difficulty"), transform::zerobytes(0));
     bob.insert(String::from("totalDifficulty"), transform::zerobytes(0));
@@ -281,6 +475,23 @@ where
     Ok(Value::Object(bob))
 }
 
+/// Indexes every transaction in `block`'s batches by its signature.
+///
+/// Transaction bodies are already present in full on the block, so this
+/// lets callers resolve all of a block's transactions in one pass instead
+/// of issuing one validator request per transaction. Borrows the
+/// transactions rather than cloning them, since this runs over every
+/// transaction in the block - doubling that isn't free for large blocks.
+fn index_transactions_by_signature(block: &Block) -> HashMap<String, &Transaction> {
+    let mut txns_by_sig = HashMap::new();
+    for batch in &block.batches {
+        for txn in &batch.transactions {
+            txns_by_sig.insert(txn.header_signature.clone(), txn);
+        }
+    }
+    txns_by_sig
+}
+
 /// Returns the number of transactions for the given block as a hex string
 fn get_block_transaction_count<T>(
     block_key: BlockKey,
@@ -289,6 +500,8 @@ fn get_block_transaction_count<T>(
 where
     T: MessageSender,
 {
+    flow_control::FLOW_CONTROL.charge(flow_control::DEFAULT_CALLER, "get_block_transaction_count", 0)?;
+
     let block = match client.get_block(block_key) {
         Ok(b) => b,
         Err(error) => match error {
@@ -309,3 +522,93 @@ where
             .fold(0, |acc, batch| acc + batch.transactions.len()),
     ))
 }
+
+/// Returns the number of uncles for the given block as a hex string, or
+/// `0x0` since Sawtooth has no notion of uncle blocks.
+fn get_uncle_count<T>(block_key: BlockKey, client: ValidatorClient<T>) -> Result<Value, Error>
+where
+    T: MessageSender,
+{
+    match client.get_block(block_key) {
+        Ok(_) => Ok(transform::num_to_hex(&0u64)),
+        Err(error) => match error {
+            ClientError::NoResource => Ok(Value::Null),
+            _ => {
+                error!("{:?}", error);
+                Err(Error::internal_error())
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protobuf::RepeatedField;
+    use sawtooth_sdk::messages::batch::Batch;
+
+    fn txn(signature: &str) -> Transaction {
+        let mut txn = Transaction::new();
+        txn.header_signature = signature.into();
+        txn
+    }
+
+    fn batch(signatures: &[&str]) -> Batch {
+        let mut batch = Batch::new();
+        batch.transactions = RepeatedField::from_vec(signatures.iter().map(|s| txn(s)).collect());
+        batch
+    }
+
+    #[test]
+    fn indexes_every_transaction_across_all_batches() {
+        let mut block = Block::new();
+        block.batches = RepeatedField::from_vec(vec![batch(&["a", "b"]), batch(&["c"])]);
+
+        let txns_by_sig = index_transactions_by_signature(&block);
+
+        assert_eq!(txns_by_sig.len(), 3);
+        for sig in &["a", "b", "c"] {
+            assert_eq!(
+                txns_by_sig.get(*sig).map(|t| t.header_signature.as_str()),
+                Some(*sig)
+            );
+        }
+    }
+
+    #[test]
+    fn empty_block_indexes_no_transactions() {
+        let block = Block::new();
+        assert!(index_transactions_by_signature(&block).is_empty());
+    }
+
+    // `index_transactions_by_signature` takes only `&Block` - no client
+    // handle - so resolving every transaction of a multi-transaction block
+    // through it is structurally a single pass over data already in hand,
+    // not one validator round-trip per transaction. This is the guard
+    // against regressing to the old `get_transaction_and_block`-per-txn
+    // path: that path needed a client to call, and this one has none to
+    // call. (This test does not drive `get_block_obj` through a mock
+    // `ValidatorClient`, so it does not count validator requests
+    // end-to-end.)
+    #[test]
+    fn resolves_every_transaction_of_a_large_multi_batch_block_in_one_pass() {
+        let signatures: Vec<String> = (0..200).map(|i| format!("txn-{}", i)).collect();
+        let batches: Vec<Batch> = signatures
+            .chunks(7)
+            .map(|sigs| {
+                let refs: Vec<&str> = sigs.iter().map(String::as_str).collect();
+                batch(&refs)
+            })
+            .collect();
+
+        let mut block = Block::new();
+        block.batches = RepeatedField::from_vec(batches);
+
+        let txns_by_sig = index_transactions_by_signature(&block);
+
+        assert_eq!(txns_by_sig.len(), signatures.len());
+        for sig in &signatures {
+            assert!(txns_by_sig.contains_key(sig));
+        }
+    }
+}
@@ -0,0 +1,333 @@
+/*
+ * Copyright 2018 Intel Corporation
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * ------------------------------------------------------------------------------
+ */
+
+//! Request cost accounting for the RPC dispatcher, modeled on the
+//! light-client credit system: each caller key holds a credit balance
+//! that recharges linearly with wall-clock time up to a configured
+//! maximum, and every request is charged against it before it runs.
+//!
+//! `FlowControl` itself accounts per caller key and is ready for real
+//! per-connection identity. Until `RequestHandler` dispatch threads that
+//! identity down to handlers, callers in `calls::block` charge against a
+//! single shared key (see `DEFAULT_CALLER`), which makes this a global
+//! rate limiter rather than per-client isolation.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use jsonrpc_core::{Error, ErrorCode};
+use lazy_static::lazy_static;
+
+/// The base cost of a method call, plus an additional cost per item (e.g.
+/// per transaction) when the method's work scales with the size of its
+/// result.
+#[derive(Clone, Copy, Debug)]
+pub struct MethodCost {
+    pub base: f64,
+    pub per_item: f64,
+}
+
+impl MethodCost {
+    pub fn new(base: f64, per_item: f64) -> Self {
+        MethodCost { base, per_item }
+    }
+
+    fn cost(&self, item_count: usize) -> f64 {
+        self.base + self.per_item * item_count as f64
+    }
+}
+
+/// Maps RPC method names to their `MethodCost`. Methods with no entry are
+/// not charged, so the table only needs entries for methods expensive
+/// enough to warrant flow control.
+#[derive(Clone, Debug, Default)]
+pub struct CostTable {
+    costs: HashMap<String, MethodCost>,
+}
+
+impl CostTable {
+    pub fn new() -> Self {
+        CostTable {
+            costs: HashMap::new(),
+        }
+    }
+
+    pub fn set(&mut self, method: &str, cost: MethodCost) {
+        self.costs.insert(method.into(), cost);
+    }
+
+    fn cost_of(&self, method: &str, item_count: usize) -> f64 {
+        self.costs
+            .get(method)
+            .map(|c| c.cost(item_count))
+            .unwrap_or(0.0)
+    }
+}
+
+/// Linear recharge parameters for a client's credit balance.
+#[derive(Clone, Copy, Debug)]
+pub struct RechargeConfig {
+    /// Credits recharged per second.
+    pub rate: f64,
+    /// The maximum balance a client may accrue.
+    pub max: f64,
+}
+
+struct Balance {
+    amount: f64,
+    last_recharge: Instant,
+}
+
+/// Flow control over RPC dispatch: tracks a credit balance per caller and
+/// rejects requests that would overdraw it.
+pub struct FlowControl {
+    cost_table: CostTable,
+    recharge: RechargeConfig,
+    balances: Mutex<HashMap<String, Balance>>,
+}
+
+impl FlowControl {
+    pub fn new(cost_table: CostTable, recharge: RechargeConfig) -> Self {
+        FlowControl {
+            cost_table,
+            recharge,
+            balances: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Charges `caller` for one call to `method` covering `item_count`
+    /// items (e.g. transactions in a block). Recharges the caller's
+    /// balance for elapsed time first; if the resulting balance can't
+    /// cover the cost, the balance is left untouched and an error is
+    /// returned instead of deducting.
+    pub fn charge(&self, caller: &str, method: &str, item_count: usize) -> Result<(), Error> {
+        self.charge_at(caller, method, item_count, Instant::now())
+    }
+
+    fn charge_at(
+        &self,
+        caller: &str,
+        method: &str,
+        item_count: usize,
+        now: Instant,
+    ) -> Result<(), Error> {
+        let cost = self.cost_table.cost_of(method, item_count);
+
+        let mut balances = self.balances.lock().expect("flow control lock poisoned");
+        let balance = balances.entry(caller.into()).or_insert_with(|| Balance {
+            amount: self.recharge.max,
+            last_recharge: now,
+        });
+
+        let elapsed = if now > balance.last_recharge {
+            let duration = now.duration_since(balance.last_recharge);
+            duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1e9
+        } else {
+            0.0
+        };
+        balance.amount = (balance.amount + elapsed * self.recharge.rate).min(self.recharge.max);
+        balance.last_recharge = now;
+
+        if balance.amount < cost {
+            return Err(not_enough_credits());
+        }
+
+        balance.amount -= cost;
+        Ok(())
+    }
+}
+
+/// Placeholder caller key shared by every request until the RPC
+/// dispatcher threads real per-connection identity down to handlers.
+///
+/// Charging every caller against this one key makes `FLOW_CONTROL` a
+/// global rate limiter, not per-client isolation: one caller exhausting
+/// the shared balance will get every other caller's requests rejected
+/// too. That's an explicit, temporary scoping decision, not the
+/// intended end state - swap this for a real per-connection identity
+/// (and `FlowControl` will account it per caller with no other changes)
+/// as soon as dispatch can provide one.
+pub const DEFAULT_CALLER: &str = "default";
+
+lazy_static! {
+    /// Process-wide flow control shared by every handler that calls
+    /// `FLOW_CONTROL.charge`. `get_block_obj` is the expensive one: a
+    /// `full=true` request against a large block does as much work as
+    /// fetching every one of its transactions individually, so it's
+    /// charged a base cost plus a per-transaction cost.
+    /// `get_block_transaction_count` only ever looks at transaction
+    /// counts, so it's charged a flat base cost.
+    pub static ref FLOW_CONTROL: FlowControl = {
+        let mut costs = CostTable::new();
+        costs.set("get_block_obj", MethodCost::new(10.0, 1.0));
+        costs.set("get_block_transaction_count", MethodCost::new(2.0, 0.0));
+
+        FlowControl::new(
+            costs,
+            RechargeConfig {
+                rate: 5.0,
+                max: 1000.0,
+            },
+        )
+    };
+}
+
+/// The dedicated JSON-RPC error returned when a caller doesn't have
+/// enough credits to cover a request, analogous to the light protocol's
+/// "not enough credits" rejection.
+fn not_enough_credits() -> Error {
+    Error {
+        code: ErrorCode::ServerError(-32097),
+        message: "Not enough credits".into(),
+        data: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn flow_control() -> FlowControl {
+        let mut costs = CostTable::new();
+        costs.set("eth_getBlockByNumber", MethodCost::new(10.0, 1.0));
+        costs.set("eth_getBlockTransactionCountByNumber", MethodCost::new(5.0, 0.0));
+
+        FlowControl::new(
+            costs,
+            RechargeConfig {
+                rate: 2.0,
+                max: 100.0,
+            },
+        )
+    }
+
+    #[test]
+    fn first_request_starts_from_a_full_balance() {
+        let fc = flow_control();
+        let now = Instant::now();
+        // 10 base + 90 transactions spends exactly the full 100 credits.
+        assert!(fc
+            .charge_at("alice", "eth_getBlockByNumber", 90, now)
+            .is_ok());
+    }
+
+    #[test]
+    fn balance_recharges_linearly_with_elapsed_time() {
+        let fc = flow_control();
+        let t0 = Instant::now();
+
+        // Spend all 100 credits on a block with 90 transactions (10 base + 90).
+        assert!(fc.charge_at("alice", "eth_getBlockByNumber", 90, t0).is_ok());
+
+        // Immediately retrying with no elapsed time should fail: balance is 0.
+        assert!(fc
+            .charge_at("alice", "eth_getBlockTransactionCountByNumber", 0, t0)
+            .is_err());
+
+        // After 1 second at a rate of 2/s, the balance has recharged to 2,
+        // not yet enough for the 5-credit transaction count call...
+        let t1 = t0 + Duration::from_secs(1);
+        assert!(fc
+            .charge_at("alice", "eth_getBlockTransactionCountByNumber", 0, t1)
+            .is_err());
+
+        // ...but after 5 seconds the balance has recharged to 10, enough
+        // to cover the 5-credit cost.
+        let t2 = t0 + Duration::from_secs(5);
+        assert!(fc
+            .charge_at("alice", "eth_getBlockTransactionCountByNumber", 0, t2)
+            .is_ok());
+    }
+
+    #[test]
+    fn recharge_never_exceeds_the_configured_maximum() {
+        let fc = flow_control();
+        let t0 = Instant::now();
+        assert!(fc.charge_at("alice", "eth_getBlockByNumber", 0, t0).is_ok());
+
+        // A huge amount of elapsed time should cap the balance at `max`,
+        // not let it grow unbounded.
+        let t1 = t0 + Duration::from_secs(1_000_000);
+        assert!(fc
+            .charge_at("alice", "eth_getBlockByNumber", 89, t1)
+            .is_ok());
+
+        // That 99-credit charge leaves 1 credit of the capped 100, so a
+        // further 10-credit charge with no more elapsed time is rejected.
+        assert!(fc
+            .charge_at("alice", "eth_getBlockByNumber", 0, t1)
+            .is_err());
+    }
+
+    #[test]
+    fn unknown_methods_are_not_charged() {
+        let fc = flow_control();
+        let t0 = Instant::now();
+        for _ in 0..1000 {
+            assert!(fc.charge_at("alice", "eth_blockNumber", 0, t0).is_ok());
+        }
+    }
+
+    #[test]
+    fn balances_are_tracked_independently_per_caller() {
+        let fc = flow_control();
+        let t0 = Instant::now();
+        assert!(fc.charge_at("alice", "eth_getBlockByNumber", 90, t0).is_ok());
+        assert!(fc.charge_at("bob", "eth_getBlockByNumber", 90, t0).is_ok());
+    }
+
+    // These exercise the process-wide `FLOW_CONTROL` singleton that
+    // `calls::block` charges against. Each test uses a caller name unique
+    // to it so they don't interfere with each other's balance despite
+    // sharing the same global state.
+    #[test]
+    fn flow_control_singleton_charges_get_block_obj_per_transaction() {
+        let now = Instant::now();
+        assert!(FLOW_CONTROL
+            .charge_at("singleton-test-full-block", "get_block_obj", 50, now)
+            .is_ok());
+
+        // A request against a block with as many transactions as remain
+        // affordable (10 base + 930 = the remaining 940) should still succeed...
+        assert!(FLOW_CONTROL
+            .charge_at("singleton-test-full-block", "get_block_obj", 930, now)
+            .is_ok());
+
+        // ...but one more request of any size should now be rejected,
+        // since the balance is exhausted and hasn't had time to recharge.
+        assert!(FLOW_CONTROL
+            .charge_at("singleton-test-full-block", "get_block_obj", 0, now)
+            .is_err());
+    }
+
+    #[test]
+    fn flow_control_singleton_charges_get_block_transaction_count_flat_rate() {
+        let now = Instant::now();
+        for _ in 0..10 {
+            assert!(FLOW_CONTROL
+                .charge_at(
+                    "singleton-test-txn-count",
+                    "get_block_transaction_count",
+                    0,
+                    now
+                )
+                .is_ok());
+        }
+    }
+}